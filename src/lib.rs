@@ -0,0 +1,522 @@
+//! mikanbako のダウンロードエンジンを提供するライブラリです。
+//!
+//! `Downloader` に `FileToDownload` のリストを渡して実行すると、`Callback` を通じて
+//! 進捗や結果が通知されます。CLI はこのクレートの薄いラッパーとして実装されています。
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use futures::StreamExt;
+use percent_encoding::percent_decode_str;
+use reqwest::header::RANGE;
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+
+/// 再試行時の最大待機時間 (ミリ秒)。
+const MAX_RETRY_DELAY_MS: u64 = 60_000;
+
+/// チェックサムマニフェストで使用できるハッシュアルゴリズム。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha256,
+}
+
+/// 期待されるチェックサム。
+#[derive(Debug, Clone)]
+pub struct ExpectedChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub hex: String,
+}
+
+/// ダウンロード対象の 1 ファイルを表します。
+#[derive(Debug, Clone)]
+pub struct FileToDownload {
+    /// ダウンロード元の URL。
+    pub url: String,
+    /// 保存先のファイル名。`None` の場合はレスポンスの URL から推測します。
+    pub dest: Option<String>,
+    /// 検証に使用する期待チェックサム。
+    pub expected_checksum: Option<ExpectedChecksum>,
+}
+
+impl FileToDownload {
+    /// URL のみを指定して `FileToDownload` を作成します。
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            dest: None,
+            expected_checksum: None,
+        }
+    }
+}
+
+impl From<String> for FileToDownload {
+    fn from(url: String) -> Self {
+        FileToDownload::new(url)
+    }
+}
+
+/// 1 件のダウンロードで発生しうる状態。
+pub enum Status {
+    Started,
+    Progress { received: u64, total: u64 },
+    Finished,
+    Failed(anyhow::Error),
+}
+
+/// ダウンロードの進捗や結果を呼び出し側へ通知するためのコールバック。
+pub trait Callback: Send + Sync {
+    fn on_status(&self, index: usize, file: &FileToDownload, status: Status);
+}
+
+/// 同時にダウンロードできる接続数の上限。
+pub const NUMBER_OF_MAX_CONCURRENT_DOWNLOADS: usize = 100;
+
+/// ダウンロードエンジンの設定を保持します。
+pub struct Downloader {
+    pub connections: usize,
+    pub output_dir: PathBuf,
+    pub retries: u32,
+    pub retry_delay_ms: u64,
+    pub resume: bool,
+    pub connect_timeout: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub user_agent: Option<String>,
+    /// 同一ホストに対して同時に張る接続数の上限。`None` の場合は制限しません。
+    pub per_host_limit: Option<usize>,
+}
+
+impl Downloader {
+    /// 既定値で `Downloader` を作成します。
+    pub fn new(output_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            connections: 2,
+            output_dir: output_dir.into(),
+            retries: 0,
+            retry_delay_ms: 1000,
+            resume: false,
+            connect_timeout: None,
+            timeout: None,
+            user_agent: None,
+            per_host_limit: None,
+        }
+    }
+
+    /// 設定に従い `Client` を構築します。
+    fn build_client(&self) -> anyhow::Result<Client> {
+        let mut builder = Client::builder();
+
+        if let Some(d) = self.connect_timeout {
+            builder = builder.connect_timeout(d);
+        }
+        if let Some(d) = self.timeout {
+            builder = builder.timeout(d);
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// 渡されたファイル一覧をワーカープールで並行ダウンロードします。
+    pub async fn run(
+        &self,
+        files: Vec<FileToDownload>,
+        callback: Arc<dyn Callback>,
+    ) -> anyhow::Result<()> {
+        if !self.output_dir.exists() {
+            fs::create_dir_all(&self.output_dir)?;
+        }
+
+        let connections = if self.connections > NUMBER_OF_MAX_CONCURRENT_DOWNLOADS {
+            eprintln!(
+                "Warning: connections ({}) exceeds the maximum of {}; clamping.",
+                self.connections, NUMBER_OF_MAX_CONCURRENT_DOWNLOADS
+            );
+            NUMBER_OF_MAX_CONCURRENT_DOWNLOADS
+        } else {
+            self.connections
+        };
+
+        let client = Arc::new(self.build_client()?);
+        let files = Arc::new(files);
+        let semaphore = Arc::new(Semaphore::new(connections));
+        let host_semaphores: Arc<Mutex<HashMap<String, Arc<Semaphore>>>> = Arc::new(Mutex::new(HashMap::new()));
+        let work_queue: Arc<Mutex<VecDeque<usize>>> = Arc::new(Mutex::new((0..files.len()).collect()));
+        let mut handles = Vec::with_capacity(connections);
+
+        for _ in 0..connections {
+            let semaphore = semaphore.clone();
+            let host_semaphores = host_semaphores.clone();
+            let work_queue = work_queue.clone();
+            let files = files.clone();
+            let client = client.clone();
+            let callback = callback.clone();
+            let output_dir = self.output_dir.clone();
+            let resume = self.resume;
+            let retries = self.retries;
+            let retry_delay = self.retry_delay_ms;
+            let per_host_limit = self.per_host_limit;
+
+            handles.push(tokio::spawn(async move {
+                loop {
+                    let index = match work_queue.lock().unwrap().pop_front() {
+                        Some(index) => index,
+                        None => break,
+                    };
+                    let file = &files[index];
+
+                    // ホストごとの同時接続数を制限する場合は、追加で専用のセマフォを取得する。
+                    // 取得できなければ、このホストが空くのを待つ間に他のホストの分を進められる
+                    // よう、グローバルな接続枠を塞がずインデックスをキューへ戻す
+                    let _host_permit = match per_host_limit {
+                        Some(limit) => {
+                            let host_semaphore = host_semaphores.lock().unwrap()
+                                .entry(host_of(&file.url))
+                                .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+                                .clone();
+                            match host_semaphore.try_acquire_owned() {
+                                Ok(permit) => Some(permit),
+                                Err(_) => {
+                                    work_queue.lock().unwrap().push_back(index);
+                                    tokio::time::sleep(Duration::from_millis(50)).await;
+                                    continue;
+                                }
+                            }
+                        }
+                        None => None,
+                    };
+
+                    let _permit = semaphore.acquire().await.unwrap();
+
+                    callback.on_status(index, file, Status::Started);
+
+                    let result = download(
+                        &client, file, &output_dir, resume, retries, retry_delay,
+                        callback.as_ref(), index,
+                    ).await;
+
+                    match result {
+                        Ok(()) => callback.on_status(index, file, Status::Finished),
+                        Err(err) => callback.on_status(index, file, Status::Failed(err)),
+                    }
+                }
+            }));
+        }
+
+        for handle in handles {
+            handle.await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// 指定した URL からデータをダウンロードします。失敗した場合は `retries` 回まで、
+/// 指数バックオフで待機してから再試行します。
+async fn download(
+    client: &Client,
+    file: &FileToDownload,
+    output_dir: &Path,
+    resume: bool,
+    retries: u32,
+    retry_delay: u64,
+    callback: &dyn Callback,
+    index: usize,
+) -> anyhow::Result<()> {
+    let mut last_err = anyhow!("Failed to download {}", file.url);
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            let delay = retry_delay.saturating_mul(1u64 << (attempt - 1).min(16)).min(MAX_RETRY_DELAY_MS);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+
+        // 2 回目以降の試行は、既にディスクにある分だけ Range で再開する
+        let resume_this_attempt = resume || attempt > 0;
+        match download_once(client, file, output_dir, resume_this_attempt, callback, index).await {
+            Ok(()) => return Ok(()),
+            Err(err) => last_err = err,
+        }
+    }
+
+    Err(last_err)
+}
+
+/// `download` の 1 回分の試行本体です。
+async fn download_once(
+    client: &Client,
+    file: &FileToDownload,
+    output_dir: &Path,
+    resume: bool,
+    callback: &dyn Callback,
+    index: usize,
+) -> anyhow::Result<()> {
+    // 再開対象のファイルがあればその長さを取得し、Range ヘッダーを組み立てる
+    // (保存先が明示されていない場合、実際の保存ファイル名はリダイレクト後の URL から
+    // 決まるため、ここではあくまで再開候補を探すための推測名として使う)
+    let resume_candidate = file.dest.clone().or_else(|| guess_filename(&file.url));
+    let existing_len = if resume {
+        resume_candidate.as_ref().map_or(0, |dest| existing_file_len(output_dir, dest))
+    } else {
+        0
+    };
+
+    let mut req = client.get(&file.url);
+    if existing_len > 0 {
+        req = req.header(RANGE, format!("bytes={}-", existing_len));
+    }
+
+    // 接続を確立
+    let res = match req.send().await {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Error while downloading file {:?}", err);
+            return Err(anyhow!("{:?}", err));
+        }
+    };
+
+    // 出力先ファイルの準備 (保存先が明示されていなければ、リダイレクト後の URL から決める)
+    let filename = match &file.dest {
+        Some(dest) => dest.clone(),
+        None => match res.url().path_segments() {
+            Some(segments) => percent_decode_str(
+                segments.last().unwrap()).decode_utf8_lossy().to_string(),
+            None => gen_filename()?,
+        },
+    };
+    let filepath = output_dir.join(&filename);
+
+    // サーバーが Range に応じたかどうか
+    let is_resuming = existing_len > 0 && res.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+
+    // 416 Range Not Satisfiable は、要求した範囲が存在しない、つまり既に全バイトを
+    // 取得済みであることを意味する。トランケートして再ダウンロードせず完了とみなす
+    if existing_len > 0 && res.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // 検証対象であれば、ディスク上の既存ファイル全体のダイジェストを照合する
+        if let Some(expected) = &file.expected_checksum {
+            let mut hasher = ChecksumHasher::new(expected.algorithm);
+            hasher.update_from_file(&filepath)?;
+            let actual = hasher.finalize_hex();
+            if actual != expected.hex {
+                fs::remove_file(&filepath)?;
+                return Err(anyhow!(
+                    "Checksum mismatch for {}: expected {}, got {}", filename, expected.hex, actual
+                ));
+            }
+        }
+        callback.on_status(index, file, Status::Progress { received: existing_len, total: existing_len });
+        return Ok(());
+    }
+
+    // サイズの取得。received は実際に再開している場合のみ既存バイト数から始める
+    let content_length = res.content_length().unwrap_or_else(|| 0);
+    let total = if is_resuming { existing_len + content_length } else { content_length };
+    if total != 0 {
+        let received = if is_resuming { existing_len } else { 0 };
+        callback.on_status(index, file, Status::Progress { received, total });
+    }
+
+    let mut output_file = if is_resuming {
+        fs::OpenOptions::new().create(true).append(true).open(&filepath)?
+    } else {
+        fs::OpenOptions::new().create(true).write(true).truncate(true).open(&filepath)?
+    };
+
+    // チェックサムの検証対象であればハッシュ計算を行いながらダウンロードする。
+    // 再開時は既にディスクにある分もハッシュに含める必要があるため、先に読み込んでおく
+    let mut hasher = file.expected_checksum.as_ref().map(|expected| ChecksumHasher::new(expected.algorithm));
+    if is_resuming {
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update_from_file(&filepath)?;
+        }
+    }
+    let mut received = if is_resuming { existing_len } else { 0 };
+
+    // ダウンロード中
+    let mut stream = res.bytes_stream();
+    while let Some(item) = stream.next().await {
+        let chunk = item.or(Err(anyhow!("Error while downloading file")))?;
+
+        output_file.write(&chunk)?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&chunk);
+        }
+
+        received += chunk.len() as u64;
+        callback.on_status(index, file, Status::Progress { received, total });
+    }
+
+    // チェックサムの照合
+    if let (Some(hasher), Some(expected)) = (hasher, &file.expected_checksum) {
+        let actual = hasher.finalize_hex();
+        if actual != expected.hex {
+            drop(output_file);
+            fs::remove_file(&filepath)?;
+            return Err(anyhow!(
+                "Checksum mismatch for {}: expected {}, got {}", filename, expected.hex, actual
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// ストリームを読みながら段階的にダイジェストを計算するためのハッシャー。
+enum ChecksumHasher {
+    Md5(md5::Context),
+    Sha256(Sha256),
+}
+
+impl ChecksumHasher {
+    fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Md5 => ChecksumHasher::Md5(md5::Context::new()),
+            ChecksumAlgorithm::Sha256 => ChecksumHasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            ChecksumHasher::Md5(ctx) => ctx.consume(data),
+            ChecksumHasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// 再開時に既にディスクへ書き込まれている分を、ストリーム読み込み前にハッシュへ取り込みます。
+    fn update_from_file(&mut self, filepath: &Path) -> anyhow::Result<()> {
+        let mut existing = fs::File::open(filepath)?;
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let n = existing.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            self.update(&buf[..n]);
+        }
+
+        Ok(())
+    }
+
+    fn finalize_hex(self) -> String {
+        match self {
+            ChecksumHasher::Md5(ctx) => format!("{:x}", ctx.compute()),
+            ChecksumHasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+        }
+    }
+}
+
+/// チェックサムマニフェストを読み込み、ファイル名またはURLから期待されるダイジェストへのマップを返します。
+pub fn load_checksum_manifest(filepath: impl AsRef<Path>) -> anyhow::Result<HashMap<String, ExpectedChecksum>> {
+    let fp = fs::File::open(filepath.as_ref())?;
+    let reader = BufReader::new(fp);
+
+    let mut checksums = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let digest_spec = parts.next().unwrap_or("");
+        let filename = parts.next().unwrap_or("").trim();
+        if filename.is_empty() {
+            return Err(anyhow!("Invalid checksum manifest line: {}", line));
+        }
+
+        let mut digest_parts = digest_spec.splitn(2, ':');
+        let algorithm = match digest_parts.next() {
+            Some("md5") => ChecksumAlgorithm::Md5,
+            Some("sha256") => ChecksumAlgorithm::Sha256,
+            _ => return Err(anyhow!("Unsupported checksum algorithm in line: {}", line)),
+        };
+        let hex = digest_parts.next()
+            .ok_or_else(|| anyhow!("Invalid checksum manifest line: {}", line))?
+            .to_lowercase();
+
+        checksums.insert(filename.to_string(), ExpectedChecksum { algorithm, hex });
+    }
+
+    Ok(checksums)
+}
+
+/// URL のパスの最後のセグメントから、保存時に使うファイル名を推測します。
+pub fn guess_filename(url: &str) -> Option<String> {
+    let filename = reqwest::Url::parse(url).ok().and_then(|u| {
+        u.path_segments().and_then(|mut s| s.next_back().map(|s| s.to_string()))
+    })?;
+
+    if filename.is_empty() {
+        None
+    } else {
+        Some(percent_decode_str(&filename).decode_utf8_lossy().to_string())
+    }
+}
+
+/// 指定したファイルが出力先に既に存在する場合、そのサイズを返します。
+fn existing_file_len(output_dir: &Path, filename: &str) -> u64 {
+    fs::metadata(output_dir.join(filename)).map(|m| m.len()).unwrap_or(0)
+}
+
+/// URL からホスト名を取り出します。パースに失敗した場合は URL 全体をキーとして扱います。
+fn host_of(url: &str) -> String {
+    reqwest::Url::parse(url).ok()
+        .and_then(|u| u.host_str().map(|s| s.to_string()))
+        .unwrap_or_else(|| url.to_string())
+}
+
+/// URL からファイル名が特定できない場合に、現在時刻 (UNIX エポックからのミリ秒) からファイル名を生成します。
+fn gen_filename() -> anyhow::Result<String> {
+    let now = std::time::SystemTime::now();
+    let secs = now.duration_since(std::time::UNIX_EPOCH)?.as_millis();
+
+    Ok(secs.to_string())
+}
+
+/// 連番の URL リストを作成します。
+pub fn create_sequential_download_list<S>(url: S, start: i64, end: i64) -> anyhow::Result<Vec<String>>
+    where S: Into<String>
+{
+    let url = url.into();
+
+    if start > end {
+        return Err(anyhow!("The value of start must be less than or equal to end"));
+    }
+
+    let mut urls = Vec::with_capacity((end - start) as usize + 1);
+    for i in start..=end {
+        let url = url.replace("{}", &i.to_string());
+        urls.push(url);
+    }
+
+    Ok(urls)
+}
+
+/// 指定したファイルを読み込み、ダウンロードする URL のリストを返します。
+pub fn create_download_list_from_file(filepath: impl AsRef<Path>) -> anyhow::Result<Vec<String>> {
+    let fp = fs::File::open(&filepath.as_ref())?;
+    let mut reader = BufReader::new(fp);
+
+    let mut files = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+
+        if line.len() != 0 {
+            files.push(line.replace("\r", "").replace("\n", ""));
+        }
+    }
+
+    Ok(files)
+}
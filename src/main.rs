@@ -1,16 +1,15 @@
-use std::{fs, time};
-use std::io::{BufRead, BufReader, Write};
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::anyhow;
 use clap::{App, Arg, ArgMatches};
-use futures::StreamExt;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use percent_encoding::percent_decode_str;
-use reqwest::Client;
-use tokio::sync::Semaphore;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use mikanbako::{
+    create_download_list_from_file, create_sequential_download_list, guess_filename,
+    load_checksum_manifest, Callback, Downloader, FileToDownload, Status,
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -29,31 +28,43 @@ async fn main() -> anyhow::Result<()> {
         create_download_list_from_file(filepath)?
     };
 
-    // 出力先ディレクトリの準備
-    let output_dir = PathBuf::from(matches.value_of("output").unwrap());
-    if !output_dir.exists() {
-        fs::create_dir_all(&output_dir)?;
-    }
+    // チェックサムマニフェストの読み込み
+    let checksums = match matches.value_of("checksums") {
+        Some(filepath) => Some(load_checksum_manifest(filepath)?),
+        None => None,
+    };
 
-    // プログレスバーの準備
-    let bars = Arc::new(MultiProgress::new());
+    // 保存先は指定せず、実際の保存ファイル名はリダイレクト後の URL から決めさせる。
+    // URL から推測したファイル名は、チェックサムマニフェストの照合キーとしてのみ使う
+    let files: Vec<FileToDownload> = urls.into_iter().map(|url| {
+        let guessed_filename = guess_filename(&url);
+        let expected_checksum = checksums.as_ref().and_then(|checksums| {
+            guessed_filename.as_ref().and_then(|filename| checksums.get(filename))
+                .or_else(|| checksums.get(&url))
+        }).cloned();
+
+        FileToDownload { url, dest: None, expected_checksum }
+    }).collect();
+
+    // ダウンローダーの準備
+    let mut downloader = Downloader::new(PathBuf::from(matches.value_of("output").unwrap()));
+    downloader.connections = matches.value_of("connections").unwrap().parse().unwrap();
+    downloader.resume = matches.is_present("resume");
+    downloader.retries = matches.value_of("retries").unwrap().parse().unwrap();
+    downloader.retry_delay_ms = matches.value_of("retry-delay").unwrap().parse().unwrap();
+    downloader.connect_timeout = matches.value_of("connect-timeout")
+        .map(|v| v.parse()).transpose()?.map(Duration::from_millis);
+    downloader.timeout = matches.value_of("timeout")
+        .map(|v| v.parse()).transpose()?.map(Duration::from_millis);
+    downloader.user_agent = matches.value_of("user-agent").map(|v| v.to_string());
+    downloader.per_host_limit = matches.value_of("per-host-limit")
+        .map(|v| v.parse()).transpose()?;
 
-    let main_bar = Arc::new(bars.add(ProgressBar::new(urls.len() as u64)));
+    // プログレスバーの準備
+    let bars = MultiProgress::new();
+    let main_bar = Arc::new(bars.add(ProgressBar::new(files.len() as u64)));
     main_bar.set_style(create_main_bar_style());
 
-    // ダウンロード準備
-    let connections: usize = matches.value_of("connections").unwrap().parse().unwrap();
-    let connections = if connections <= 10 {
-        connections
-    } else {
-        2
-    };
-    let urls = Arc::new(urls);
-    let semaphore = Arc::new(Semaphore::new(connections));
-    let counter = Arc::new(AtomicUsize::new(0));
-    let mut handles = Vec::with_capacity(connections);
-
-    // メインバーを更新するスレッドを生成
     tokio::spawn({
         let main_bar = main_bar.clone();
         async move {
@@ -67,45 +78,128 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
-    // ダウンロードジョブを処理するスレッドを生成
-    for _ in 0..connections {
-        let semaphore = semaphore.clone();
-        let counter = counter.clone();
-        let urls = urls.clone();
-        let bars = bars.clone();
-        let output_dir = output_dir.clone();
-        let main_bar = main_bar.clone();
+    let callback = Arc::new(CliCallback::new(main_bar.clone()));
+    downloader.run(files, callback).await?;
 
-        handles.push(tokio::spawn(async move {
-            loop {
-                let _ = semaphore.acquire().await.unwrap();
+    main_bar.finish();
 
-                let index = counter.fetch_add(1, Ordering::Acquire);
-                if index >= urls.len() {
-                    break;
-                }
-                let url = &urls[index];
+    Ok(())
+}
+
+/// `Downloader` からの通知を受け取り、プログレスバーへ反映するコールバック。
+struct CliCallback {
+    progress: ProgressWrapper,
+    last_received: Mutex<HashMap<usize, u64>>,
+    last_total: Mutex<HashMap<usize, u64>>,
+}
 
-                let bar = bars.add(ProgressBar::new_spinner());
+impl CliCallback {
+    fn new(main_bar: Arc<ProgressBar>) -> Self {
+        Self {
+            progress: ProgressWrapper::new(main_bar),
+            last_received: Mutex::new(HashMap::new()),
+            last_total: Mutex::new(HashMap::new()),
+        }
+    }
+}
 
-                if let Err(err) = download(url, &output_dir, &bar).await {
-                    eprintln!("{:#?}", err);
+impl Callback for CliCallback {
+    fn on_status(&self, index: usize, file: &FileToDownload, status: Status) {
+        match status {
+            Status::Started => self.progress.start_download(),
+            Status::Progress { received, total } => {
+                let prev_received = self.last_received.lock().unwrap().insert(index, received).unwrap_or(0);
+                if received > prev_received {
+                    self.progress.add_bytes_received(received - prev_received);
                 }
 
-                bars.remove(&bar);
-                main_bar.inc(1);
+                let prev_total = self.last_total.lock().unwrap().insert(index, total).unwrap_or(0);
+                if total > prev_total {
+                    self.progress.add_content_length(total - prev_total);
+                }
+            }
+            Status::Finished => {
+                if file.expected_checksum.is_some() {
+                    self.progress.mark_verified();
+                }
+                self.progress.complete();
+            }
+            Status::Failed(err) => {
+                eprintln!("{:#?}", err);
+                self.progress.complete();
             }
-        }));
+        }
+    }
+}
+
+/// 複数のダウンロードタスクの進捗を集計し、1 行のメインバーへ反映するラッパー。
+struct ProgressWrapper {
+    main_bar: Arc<ProgressBar>,
+    state: Mutex<ProgressWrapperState>,
+}
+
+#[derive(Default)]
+struct ProgressWrapperState {
+    current_bars: usize,
+    bytes_received: u64,
+    sum_bytes: u64,
+    verified: usize,
+}
+
+impl ProgressWrapper {
+    fn new(main_bar: Arc<ProgressBar>) -> Self {
+        Self {
+            main_bar,
+            state: Mutex::new(ProgressWrapperState::default()),
+        }
     }
 
-    // すべてのタスクが終了するまで待機
-    for handle in handles {
-        handle.await?;
+    /// ダウンロードの開始を記録し、実行中のダウンロード数を増やします。
+    fn start_download(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.current_bars += 1;
+        self.render(&state);
     }
 
-    main_bar.finish();
+    /// ダウンロードの終了を記録し、実行中のダウンロード数を減らしてメインバーを進めます。
+    fn complete(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.current_bars = state.current_bars.saturating_sub(1);
+        self.render(&state);
+        self.main_bar.inc(1);
+    }
 
-    Ok(())
+    /// Content-Length が判明した際に合計バイト数へ加算します。
+    fn add_content_length(&self, len: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.sum_bytes += len;
+        self.render(&state);
+    }
+
+    /// チャンクを受信する度に受信済みバイト数へ加算します。
+    fn add_bytes_received(&self, len: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.bytes_received += len;
+        self.render(&state);
+    }
+
+    /// チェックサムの照合に成功したファイル数を加算します。
+    fn mark_verified(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.verified += 1;
+        self.render(&state);
+    }
+
+    /// 現在の集計状況をメインバーのメッセージへ反映します。
+    fn render(&self, state: &ProgressWrapperState) {
+        self.main_bar.set_message(format!(
+            "{} downloads running, {} verified, {}/{}",
+            state.current_bars,
+            state.verified,
+            HumanBytes(state.bytes_received),
+            HumanBytes(state.sum_bytes),
+        ));
+    }
 }
 
 /// コマンドライン アプリケーションの初期化
@@ -143,9 +237,47 @@ fn make_app() -> App<'static> {
         .arg(Arg::new("connections")
             .long("connections")
             .short('c')
-            .about("The number of connections to download in parallel")
+            .long_about("The number of connections to download in parallel. Values above the built-in maximum are clamped to it.")
             .validator(validate_natural_number)
             .default_value("2"))
+        .arg(Arg::new("per-host-limit")
+            .long("per-host-limit")
+            .about("The maximum number of connections to a single host at a time")
+            .validator(validate_positive_number)
+            .takes_value(true))
+        .arg(Arg::new("resume")
+            .long("resume")
+            .about("Resume partially downloaded files with an HTTP Range request")
+            .long_about("Resume partially downloaded files with an HTTP Range request. The existing file is located by the filename guessed from the URL before the request is sent, so a URL that redirects to a different filename (redirecting download endpoints, \"?id=\"-style links, etc.) will not be recognized as already on disk and will be downloaded again from scratch.")
+            .takes_value(false))
+        .arg(Arg::new("checksums")
+            .long("checksums")
+            .about("The path of a manifest file mapping filenames to expected md5/sha256 checksums")
+            .takes_value(true))
+        .arg(Arg::new("retries")
+            .long("retries")
+            .about("The number of times to retry a download that fails")
+            .validator(validate_natural_number)
+            .default_value("0"))
+        .arg(Arg::new("retry-delay")
+            .long("retry-delay")
+            .about("The base delay in milliseconds between retries, doubling after each attempt")
+            .validator(validate_natural_number)
+            .default_value("1000"))
+        .arg(Arg::new("connect-timeout")
+            .long("connect-timeout")
+            .about("The timeout in milliseconds for establishing a connection")
+            .validator(validate_natural_number)
+            .takes_value(true))
+        .arg(Arg::new("timeout")
+            .long("timeout")
+            .about("The timeout in milliseconds for the whole request")
+            .validator(validate_natural_number)
+            .takes_value(true))
+        .arg(Arg::new("user-agent")
+            .long("user-agent")
+            .about("The User-Agent header to send with every request")
+            .takes_value(true))
         .after_help("The end argument is required when the url argument is specified.
 When the argument list is specified, the start and end arguments are ignored.
 If you specify both the url and list arguments, the url is processed.")
@@ -167,121 +299,13 @@ fn validate_matches(matches: &ArgMatches) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// 連番の URL リストを作成します。
-fn create_sequential_download_list<S>(url: S, start: i64, end: i64) -> anyhow::Result<Vec<String>>
-    where S: Into<String>
-{
-    let url = url.into();
-
-    if start > end {
-        return Err(anyhow!("The value of start must be less than end"));
-    }
-
-    let mut urls = Vec::with_capacity((end - start) as usize + 1);
-    for i in start..=end {
-        let url = url.replace("{}", &i.to_string());
-        urls.push(url);
-    }
-
-    Ok(urls)
-}
-
-/// 指定したファイルを読み込み、ダウンロードする URL のリストを返します。
-fn create_download_list_from_file(filepath: impl AsRef<Path>) -> anyhow::Result<Vec<String>> {
-    let fp = fs::File::open(&filepath.as_ref())?;
-    let mut reader = BufReader::new(fp);
-
-    let mut files = Vec::new();
-    loop {
-        let mut line = String::new();
-        if reader.read_line(&mut line)? == 0 {
-            break;
-        }
-
-        if line.len() != 0 {
-            files.push(line.replace("\r", "").replace("\n", ""));
-        }
-    }
-
-    Ok(files)
-}
-
-/// 指定した URL からデータをダウンロードして、指定したディレクトリに保存します。
-async fn download(
-    url: impl Into<String>,
-    output_dir: impl AsRef<Path>,
-    bar: &ProgressBar,
-) -> anyhow::Result<()> {
-    let url = url.into();
-    let client = Client::new();
-
-    // 接続を確立
-    let res = match client.get(url).send().await {
-        Ok(v) => v,
-        Err(err) => {
-            println!("Error while downloading file {:?}", err);
-            return Err(anyhow!("{:?}", err));
-        }
-    };
-
-    // サイズの取得
-    let content_length = res.content_length().unwrap_or_else(|| 0);
-
-    // 出力先ファイルの準備
-    let filename = match res.url().path_segments() {
-        Some(segments) => percent_decode_str(
-            segments.last().unwrap()).decode_utf8_lossy().to_string(),
-        None => gen_filename()?,
-    };
-    let filepath = output_dir.as_ref().join(&filename);
-    let mut file = fs::OpenOptions::new()
-        .create(true).write(true).truncate(true).open(filepath)?;
-
-    // プログレスバーの再初期化
-    if content_length != 0 {
-        bar.set_message(filename);
-        bar.set_length(content_length);
-        bar.set_style(create_bar_style());
-    }
-
-    // ダウンロード中
-    let mut stream = res.bytes_stream();
-    while let Some(item) = stream.next().await {
-        let chunk = item.or(Err(anyhow!("Error while downloading file")))?;
-
-        file.write(&chunk)?;
-
-        bar.inc(chunk.len() as u64);
-    }
-
-    // ダウンロード完了
-    bar.finish();
-
-    Ok(())
-}
-
 /// メイン プログレスバーのスタイルを返します。
 fn create_main_bar_style() -> ProgressStyle {
     ProgressStyle::default_bar()
-        .template("{elapsed_precise} [{bar:40.cyan/blue}] {pos} / {len} {percent:>3$}%")
+        .template("{elapsed_precise} [{bar:40.cyan/blue}] {pos} / {len} {percent:>3$}% {msg}")
         .progress_chars("=>-")
 }
 
-/// プログレスバーのスタイルを返します。
-fn create_bar_style() -> ProgressStyle {
-    ProgressStyle::default_bar()
-        .template("{elapsed_precise} [{bar:40.cyan/blue}] {percent:>3$}% {binary_bytes_per_sec} {bytes} {msg}")
-        .progress_chars("=>-")
-}
-
-/// URL からファイル名が特定できない場合にランダムなファイル名を生成します。
-fn gen_filename() -> anyhow::Result<String> {
-    let now = time::SystemTime::now();
-    let secs = now.duration_since(time::UNIX_EPOCH)?.as_millis();
-
-    Ok(secs.to_string())
-}
-
 /// 渡された文字列を i64 としてパースを試します。
 fn validate_number(v: &str) -> Result<(), String> {
     match v.parse::<i64>() {
@@ -297,3 +321,13 @@ fn validate_natural_number(v: &str) -> Result<(), String> {
         Err(_) => Err(format!("The value must be a positive integer")),
     }
 }
+
+/// 渡された文字列を 1 以上の usize としてパースを試します。0 はセマフォを
+/// 永久に取得不能にしてしまうため許可しません。
+fn validate_positive_number(v: &str) -> Result<(), String> {
+    match v.parse::<usize>() {
+        Ok(n) if n >= 1 => Ok(()),
+        Ok(_) => Err(format!("The value must be 1 or greater")),
+        Err(_) => Err(format!("The value must be a positive integer")),
+    }
+}